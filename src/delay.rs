@@ -2,10 +2,10 @@ use super::Timer;
 
 /// Type implementing async delays
 ///
-/// The delays are implemented in a "best-effort" way, meaning that the cpu will block for at least
-/// the amount provided, but accuracy can be affected by many factors, including interrupt usage.
-/// Make sure to use a suitable tick rate for your use case. The tick rate is defined by the currently
-/// active driver.
+/// The delays are implemented in a "best-effort" way: each one awaits a [`Timer`], which the
+/// active driver wakes via an RTC alarm once it expires, rather than busy-waiting the CPU.
+/// Accuracy can still be affected by many factors, including interrupt usage. Make sure to use a
+/// suitable tick rate for your use case. The tick rate is defined by the currently active driver.
 #[derive(Clone)]
 pub struct Delay;
 
@@ -0,0 +1,60 @@
+use core::ops::{Add, Sub};
+
+use crate::tick_hz;
+
+/// A span of time, represented in ticks of the active time driver.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Duration {
+    pub(crate) ticks: u64,
+}
+
+impl Duration {
+    /// The zero-length duration.
+    pub const MIN: Duration = Duration::from_ticks(0);
+
+    /// Construct a `Duration` from a raw tick count.
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self { ticks }
+    }
+
+    /// Construct a `Duration` from a number of seconds, at the active driver's tick rate.
+    pub fn from_secs(secs: u64) -> Self {
+        Self::from_ticks(secs * tick_hz())
+    }
+
+    /// Construct a `Duration` from a number of milliseconds, at the active driver's tick rate.
+    pub fn from_millis(millis: u64) -> Self {
+        Self::from_ticks(millis * tick_hz() / 1000)
+    }
+
+    /// Construct a `Duration` from a number of microseconds, at the active driver's tick rate.
+    pub fn from_micros(micros: u64) -> Self {
+        Self::from_ticks(micros * tick_hz() / 1_000_000)
+    }
+
+    /// Construct a `Duration` from a number of nanoseconds, at the active driver's tick rate.
+    pub fn from_nanos(nanos: u64) -> Self {
+        Self::from_ticks(nanos * tick_hz() / 1_000_000_000)
+    }
+
+    /// The number of ticks this duration spans.
+    pub const fn as_ticks(&self) -> u64 {
+        self.ticks
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration::from_ticks(self.ticks + rhs.ticks)
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Duration) -> Duration {
+        Duration::from_ticks(self.ticks - rhs.ticks)
+    }
+}
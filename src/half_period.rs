@@ -0,0 +1,119 @@
+use core::sync::atomic::{compiler_fence, AtomicU32, Ordering};
+
+/// Reconstruct a race-free timestamp from a `period` count and a raw `BITS`-wide hardware
+/// counter reading.
+///
+/// See [`HalfPeriodCounter`] for the invariant this relies on.
+pub fn now(period: u32, counter: u32, bits: u32) -> u64 {
+    let half_bits = bits - 1;
+    ((period as u64) << half_bits) + ((counter ^ ((period & 1) << half_bits)) as u64)
+}
+
+/// Hooks a [`HalfPeriodCounter`] up to the two hardware events it needs to stay race-free: the
+/// counter overflowing back to 0, and reaching its midpoint.
+pub trait HalfPeriodEvents {
+    /// Call when the hardware counter overflows (wraps back to 0).
+    fn on_overflow(&self);
+
+    /// Call when the hardware counter reaches [`HalfPeriodCounter::midpoint`].
+    fn on_midpoint(&self);
+}
+
+/// Turns a narrow, wrapping hardware counter (the nRF RTC is 24 bit, but TIMER peripherals and
+/// other MCUs are commonly 16 or 32 bit) into a `now()` that only ever grows. See
+/// [`HalfPeriodCounter::now`] for what the caller must guarantee around its counter read.
+///
+/// The counter overflows every `2^BITS` ticks, which for a fast tick rate can be far too often
+/// to be useful (the RTC at 32768 Hz overflows every ~8 minutes). The obvious fix of counting
+/// overflows in a `periods` variable and returning `periods << BITS + counter` is vulnerable to
+/// a race: if `now()` reads `periods` and `counter` on either side of an overflow, it computes a
+/// value that's off by a whole cycle.
+///
+/// To fix this, we define a "period" to be `2^(BITS-1)` ticks (half an overflow cycle):
+///
+/// - `period` is incremented on overflow (at counter value 0)
+/// - `period` is incremented "midway" between overflows (at counter value `2^(BITS-1)`)
+///
+/// So when `period` is even, `counter` is in `0..2^(BITS-1)`; when odd, `counter` is in
+/// `2^(BITS-1)..2^BITS`. Reading `period` first, then `counter`, means that if an increment
+/// races the read, the mismatched parity tells us the counter value belongs to the period we
+/// just missed, and [`now`] reconstructs the correct timestamp either way.
+pub struct HalfPeriodCounter<const BITS: u32> {
+    period: AtomicU32,
+}
+
+impl<const BITS: u32> HalfPeriodCounter<BITS> {
+    pub const fn new() -> Self {
+        Self {
+            period: AtomicU32::new(0),
+        }
+    }
+
+    /// The hardware counter value at which [`HalfPeriodEvents::on_midpoint`] must be triggered.
+    pub const fn midpoint(&self) -> u32 {
+        1 << (BITS - 1)
+    }
+
+    /// Reconstruct `now()` for the given raw hardware `counter` reading.
+    ///
+    /// `counter` is read by the caller before this loads `period`, so this method does not order
+    /// the two reads itself: the result is only correct if `period` cannot be incremented between
+    /// the caller's counter read and this call, e.g. because the caller holds a critical section
+    /// that masks the interrupt driving [`HalfPeriodEvents`]. Given that, a counter that has
+    /// already passed an overflow or the midpoint whose event is still pending is handled by the
+    /// parity check in [`now`].
+    pub fn now(&self, counter: u32) -> u64 {
+        // `counter` was already read by the caller; see the method docs.
+        let period = self.period.load(Ordering::Relaxed);
+        compiler_fence(Ordering::Acquire);
+        now(period, counter, BITS)
+    }
+}
+
+impl<const BITS: u32> HalfPeriodEvents for HalfPeriodCounter<BITS> {
+    fn on_overflow(&self) {
+        self.period.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_midpoint(&self) {
+        self.period.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(half_bits: u32) {
+        let bits = half_bits + 1;
+        let half = 1u64 << half_bits;
+        let full = half * 2;
+
+        assert_eq!(now(0, 0, bits), 0);
+        assert_eq!(now(0, 1, bits), 1);
+        assert_eq!(now(0, (half - 1) as u32, bits), half - 1);
+        assert_eq!(now(1, (half - 1) as u32, bits), full + half - 1);
+        assert_eq!(now(0, half as u32, bits), half);
+        assert_eq!(now(1, half as u32, bits), half);
+        assert_eq!(now(1, (half + 1) as u32, bits), half + 1);
+        assert_eq!(now(1, (full - 1) as u32, bits), full - 1);
+        assert_eq!(now(2, (full - 1) as u32, bits), 2 * full - 1);
+        assert_eq!(now(1, 0, bits), full);
+        assert_eq!(now(2, 0, bits), full);
+    }
+
+    #[test]
+    fn test_now_16bit() {
+        check(15);
+    }
+
+    #[test]
+    fn test_now_24bit() {
+        check(23);
+    }
+
+    #[test]
+    fn test_now_32bit() {
+        check(31);
+    }
+}
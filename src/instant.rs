@@ -0,0 +1,55 @@
+use core::ops::{Add, Sub};
+
+use crate::Duration;
+
+/// A point in time, represented in ticks of the active time driver since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    /// Return the current instant, as tracked by the active time driver.
+    pub fn now() -> Self {
+        Self { ticks: crate::now() }
+    }
+
+    /// Construct an `Instant` from a raw tick count.
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self { ticks }
+    }
+
+    /// The number of ticks elapsed since boot at this instant.
+    pub const fn as_ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// The `Duration` elapsed between `earlier` and `self`, or `None` if `earlier` is after `self`.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.ticks.checked_sub(earlier.ticks).map(Duration::from_ticks)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant::from_ticks(self.ticks + rhs.ticks)
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant::from_ticks(self.ticks - rhs.ticks)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.checked_duration_since(rhs).unwrap_or(Duration::MIN)
+    }
+}
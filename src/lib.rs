@@ -1,21 +1,127 @@
 #![no_std]
 
 mod duration;
+pub mod half_period;
 mod instant;
 mod tick;
 mod time_driver;
 mod timer;
 
+use core::cell::RefCell;
+use core::task::Waker;
+
+use cortex_m::interrupt::Mutex;
+
 pub use duration::Duration;
 pub use instant::Instant;
 use tick::*;
-pub use time_driver::RtcDriver;
+pub use time_driver::{RtcDriver, ALARM_COUNT};
 pub use timer::{Ticker, Timer, WithTimeout};
 
-extern "Rust" {
-    fn _nrf_time_now() -> u64;
+/// Object-safe facade over `RtcDriver<RTC>`, so [`GLOBAL_DRIVER`] can hold the active driver
+/// without being generic over which RTC peripheral backs it.
+pub(crate) trait Driver: Sync {
+    fn now(&self) -> u64;
+    fn schedule_wake(&self, at: u64, waker: &Waker);
+    fn on_interrupt(&self);
+    /// Number of RTC events (Overflow, Compare2, or Compare0) observed so far; see
+    /// [`RtcDriver::event_count`](crate::RtcDriver::event_count).
+    fn event_count(&self) -> u32;
+}
+
+/// The driver installed by `RtcDriver::register`, backing `now()`, `Instant::now()`, `Timer`,
+/// and `idle()`.
+static GLOBAL_DRIVER: Mutex<RefCell<Option<&'static dyn Driver>>> = Mutex::new(RefCell::new(None));
+
+/// Install `driver` as the active time driver, backing `now()`, `Timer`, and `idle()` crate-wide.
+///
+/// Called by `RtcDriver::register`; not meant to be called directly.
+pub(crate) fn register_driver(driver: &'static dyn Driver) {
+    cortex_m::interrupt::free(|cs| {
+        GLOBAL_DRIVER.borrow(cs).replace(Some(driver));
+    });
+}
+
+fn with_driver<R>(f: impl FnOnce(&dyn Driver) -> R) -> R {
+    cortex_m::interrupt::free(|cs| {
+        let driver = GLOBAL_DRIVER.borrow(cs).borrow();
+        f(driver.expect("nrf_time: no driver registered; call RtcDriver::register() during init"))
+    })
 }
 
 pub fn now() -> u64 {
-    unsafe { _nrf_time_now() }
+    with_driver(|driver| driver.now())
+}
+
+/// Register a waker to be woken once `at` (in ticks since boot) is reached.
+///
+/// This is how `Timer` lets the active driver put it to sleep instead of busy-polling.
+pub(crate) fn schedule_wake(at: u64, waker: &Waker) {
+    with_driver(|driver| driver.schedule_wake(at, waker))
+}
+
+/// Forward the RTC's interrupt to the registered driver.
+///
+/// Call this from the `#[interrupt]` handler for whichever RTC peripheral `RtcDriver::register`
+/// was given:
+///
+/// ```ignore
+/// #[interrupt]
+/// fn RTC0() {
+///     nrf_time::on_interrupt();
+/// }
+/// ```
+pub fn on_interrupt() {
+    with_driver(|driver| driver.on_interrupt())
+}
+
+/// Set the `SEVONPEND` bit in `SCB.SCR`, so a pending-but-masked interrupt still sends `WFE` an
+/// event instead of being silently deferred.
+///
+/// `cortex-m` 0.7 doesn't expose a setter for this bit, so it's poked directly; writing it twice
+/// is harmless, so `idle()` just does this unconditionally on every call rather than keying it
+/// off some one-time-init flag.
+fn set_sevonpend() {
+    const SEVONPEND: u32 = 1 << 4;
+    // SAFETY: a read-modify-write of a single, documented SCB.SCR bit; doesn't touch anything
+    // else in the register, and setting it is idempotent.
+    unsafe {
+        let scb = &*cortex_m::peripheral::SCB::ptr();
+        scb.scr.modify(|scr| scr | SEVONPEND);
+    }
+}
+
+/// Put the CPU to sleep (`WFE`) until the next RTC event (overflow, midpoint, or alarm compare).
+///
+/// Call this from an executor's idle hook when no task is ready to run. Loops on `WFE` inside a
+/// critical section, rechecking the registered driver's [`event_count`](Driver::event_count)
+/// each time around, until it differs from the count observed when `idle()` was entered -- i.e.
+/// until `on_interrupt` has actually run at least once since. A bare one-shot `WFE` isn't enough:
+/// `WFE` also wakes on unrelated events, so without the recheck `idle()` could return before the
+/// RTC itself did anything.
+///
+/// Masking interrupts for the `WFE` call (rather than leaving them enabled) is what closes the
+/// classic "interrupt fires between the readiness check and WFE" race -- but only because
+/// [`set_sevonpend`] is called first: on ARMv7-M, a pending interrupt only wakes a *masked* `WFE`
+/// if `SCB.SCR.SEVONPEND` is set; otherwise the event is lost and `WFE` can block forever.
+///
+/// Relies on the RTC's overflow/midpoint/alarm interrupts staying unmasked in the NVIC, as
+/// `time_init!` arranges, and on [`on_interrupt`] being wired up to that RTC's `#[interrupt]`
+/// handler so armed alarms actually get woken.
+pub fn idle() {
+    set_sevonpend();
+    let observed_at_entry = with_driver(|driver| driver.event_count());
+    loop {
+        let woken = cortex_m::interrupt::free(|_| {
+            if with_driver(|driver| driver.event_count()) != observed_at_entry {
+                true
+            } else {
+                cortex_m::asm::wfe();
+                false
+            }
+        });
+        if woken {
+            return;
+        }
+    }
 }
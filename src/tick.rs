@@ -0,0 +1,21 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Tick rate of the RTC with no prescaling applied (the raw LFCLK rate).
+pub const DEFAULT_TICK_HZ: u32 = 32_768;
+
+/// Tick rate of the active time driver, in Hz.
+///
+/// Defaults to [`DEFAULT_TICK_HZ`]; `RtcDriver::new` updates this to match whatever prescaler
+/// it was configured with, so `Duration`/`Instant` conversions stay correct regardless of tick
+/// rate.
+static TICK_HZ: AtomicU32 = AtomicU32::new(DEFAULT_TICK_HZ);
+
+/// The tick rate `Duration`/`Instant` conversions should use, in Hz.
+pub fn tick_hz() -> u64 {
+    TICK_HZ.load(Ordering::Relaxed) as u64
+}
+
+/// Set the tick rate used by `Duration`/`Instant` conversions.
+pub(crate) fn set_tick_hz(hz: u32) {
+    TICK_HZ.store(hz, Ordering::Relaxed);
+}
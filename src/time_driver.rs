@@ -1,60 +1,50 @@
-use core::sync::atomic::{compiler_fence, AtomicU32, Ordering};
+use core::cell::{RefCell, UnsafeCell};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Waker;
+
+use cortex_m::interrupt::{CriticalSection, Mutex};
 use nrf52833_hal::{rtc, Rtc};
 use nrf52833_hal::pac::NVIC;
 
-/// Calculate the timestamp from the period count and the tick count.
-///
-/// The RTC counter is 24 bit. Ticking at 32768hz, it overflows every ~8 minutes. This is
-/// too short. We must make it "never" overflow.
-///
-/// The obvious way would be to count overflow periods. Every time the counter overflows,
-/// increase a `periods` variable. `now()` simply does `periods << 24 + counter`. So, the logic
-/// around an overflow would look like this:
-///
-/// ```not_rust
-/// periods = 1, counter = 0xFF_FFFE --> now = 0x1FF_FFFE
-/// periods = 1, counter = 0xFF_FFFF --> now = 0x1FF_FFFF
-/// **OVERFLOW**
-/// periods = 2, counter = 0x00_0000 --> now = 0x200_0000
-/// periods = 2, counter = 0x00_0001 --> now = 0x200_0001
-/// ```
-///
-/// The problem is this is vulnerable to race conditions if `now()` runs at the exact time an
-/// overflow happens.
-///
-/// If `now()` reads `periods` first and `counter` later, and overflow happens between the reads,
-/// it would return a wrong value:
-///
-/// ```not_rust
-/// periods = 1 (OLD), counter = 0x00_0000 (NEW) --> now = 0x100_0000 -> WRONG
-/// ```
-///
-/// It fails similarly if it reads `counter` first and `periods` second.
-///
-/// To fix this, we define a "period" to be 2^23 ticks (instead of 2^24). One "overflow cycle" is 2 periods.
-///
-/// - `period` is incremented on overflow (at counter value 0)
-/// - `period` is incremented "midway" between overflows (at counter value 0x80_0000)
-///
-/// Therefore, when `period` is even, counter is in 0..0x7f_ffff. When odd, counter is in 0x80_0000..0xFF_FFFF
-/// This allows for now() to return the correct value even if it races an overflow.
-///
-/// To get `now()`, `period` is read first, then `counter` is read. If the counter value matches
-/// the expected range for the `period` parity, we're done. If it doesn't, this means that
-/// a new period start has raced us between reading `period` and `counter`, so we assume the `counter` value
-/// corresponds to the next period.
-///
-/// `period` is a 32bit integer, so It overflows on 2^32 * 2^23 / 32768 seconds of uptime, which is 34865
-/// years. For comparison, flash memory like the one containing your firmware is usually rated to retain
-/// data for only 10-20 years. 34865 years is long enough!
-fn calc_now(period: u32, counter: u32) -> u64 {
-    ((period as u64) << 23) + ((counter ^ ((period & 1) << 23)) as u64)
+use crate::half_period::{HalfPeriodCounter, HalfPeriodEvents};
+
+/// Number of timers that may have an outstanding alarm registered at once. Exceeding this many
+/// concurrently-pending [`Timer`](crate::Timer)/[`Ticker`](crate::Ticker) instances panics in
+/// debug builds; in release builds the least-recently (re)armed one is silently evicted to make
+/// room and never fires (see [`RtcDriver::schedule_wake`]).
+pub const ALARM_COUNT: usize = 4;
+
+/// The nRF RTC counter is 24 bit.
+const RTC_BITS: u32 = 24;
+
+struct AlarmState {
+    timestamp: u64,
+    waker: Waker,
+    /// Monotonically increasing per `schedule_wake` call, so eviction can pick the
+    /// least-recently (re)armed slot when the table is full.
+    seq: u32,
 }
 
+/// Declare and initialize the global time driver for an RTC peripheral.
+///
+/// Generates a module `$name` with an `init(rtc, nvic)` function that creates the driver and
+/// registers it as the active one; `nrf_time::now()`, `Instant::now()`, `Timer`, and `idle()`
+/// all resolve against it from anywhere in the crate after that, no further wiring needed beyond
+/// forwarding the RTC's `#[interrupt]` handler to [`on_interrupt`](crate::on_interrupt).
+///
+/// `rtc` must already be constructed with the prescaler named here (`Rtc::new(peripheral,
+/// prescaler)`) -- `nrf-hal-common` only lets the prescaler be set at construction time, so this
+/// macro can't apply it for you; getting the two values out of sync will just desync
+/// `Duration`/`Instant` conversions from the real tick rate, not panic or error.
 #[macro_export]
 macro_rules! time_init {
-    // Take the name of the RTC peripheral
+    // Take the name of the RTC peripheral, ticking at the raw 32768 Hz LFCLK rate.
     ($name:ident: $RTC:ident) => {
+        $crate::time_init!($name: $RTC, prescaler: 0);
+    };
+    // Take the name of the RTC peripheral and the RTC PRESCALER value `rtc` was constructed
+    // with, for a tick rate of `32768 / (prescaler + 1)` Hz.
+    ($name:ident: $RTC:ident, prescaler: $prescaler:expr) => {
         mod $name {
             use ::core::cell::RefCell;
             use ::cortex_m::interrupt::Mutex;
@@ -65,93 +55,262 @@ macro_rules! time_init {
 
             pub fn init(rtc: Rtc<pac::$RTC>, nvic: &mut pac::NVIC) {
                 cortex_m::interrupt::free(|cs| {
-                    DRIVER.borrow(cs).replace(Some(RtcDriver::new(rtc, nvic)));
-                });
-            }
+                    DRIVER.borrow(cs).replace(Some(RtcDriver::new(rtc, nvic, $prescaler)));
 
-            pub fn now() -> u64 {
-                cortex_m::interrupt::free(|cs| {
-                    DRIVER.borrow(cs)
-                        .borrow()
-                        .as_ref()
-                        .expect("Time driver not initialized")
-                        .now()
-                })
+                    // SAFETY: `DRIVER` is a `static`, so the value just placed inside it lives
+                    // for the rest of the program; `init` must only be called once, so nothing
+                    // ever invalidates this reference by replacing it again.
+                    let driver: &'static RtcDriver<pac::$RTC> =
+                        unsafe { &*(DRIVER.borrow(cs).borrow().as_ref().unwrap() as *const _) };
+                    driver.register();
+                });
             }
         }
     };
 }
 
 pub struct RtcDriver<RTC: rtc::Instance> {
-    rtc: Rtc<RTC>,
-    /// Number of 2^23 periods elapsed since boot.
-    period: AtomicU32,
+    /// Wrapped in an `UnsafeCell` because a handful of `Rtc` methods (`set_compare`,
+    /// `enable_interrupt`) require `&mut self`, but this driver is shared and called from
+    /// `&self` contexts (including the ISR). Every place that needs `&mut` access goes through
+    /// [`Self::rtc_mut`], which is only ever reached from inside a `cortex_m::interrupt::free`
+    /// critical section, so there's never a second live access to race with it.
+    rtc: UnsafeCell<Rtc<RTC>>,
+    period: HalfPeriodCounter<RTC_BITS>,
+    /// Alarms registered by `Timer` futures, armed onto Compare0.
+    alarms: Mutex<RefCell<[Option<AlarmState>; ALARM_COUNT]>>,
+    /// Source of `AlarmState::seq`, so `schedule_wake` can tell which slot was armed longest ago.
+    next_seq: AtomicU32,
+    /// Bumped once per `on_interrupt` call, so [`crate::idle`] can tell an RTC event fired while
+    /// it was deciding whether to sleep again.
+    events: AtomicU32,
+    /// Bumped every time `schedule_wake` has to evict an outstanding alarm because all
+    /// [`ALARM_COUNT`] slots were already in use; see [`Self::evicted_alarm_count`].
+    evicted_alarms: AtomicU32,
 }
 
-impl<RTC: rtc::Instance> RtcDriver<RTC> {
-    pub fn new(rtc: Rtc<RTC>, nvic: &mut NVIC) -> Self {
+// SAFETY: the `UnsafeCell` in `rtc` is only ever mutated through `rtc_mut`, which is only ever
+// called from within a `cortex_m::interrupt::free` critical section; this target is single-core,
+// so there's never a second concurrent access for that to race with.
+unsafe impl<RTC: rtc::Instance + Send> Sync for RtcDriver<RTC> {}
+
+impl<RTC: rtc::Instance + Send> RtcDriver<RTC> {
+    /// Wrap an `Rtc` constructed with `Rtc::new(rtc, prescaler)`, ticking at
+    /// `32768 / (prescaler + 1)` Hz (`prescaler = 0` gives the raw 32768 Hz LFCLK rate).
+    ///
+    /// `nrf-hal-common` sets the prescaler at `Rtc::new` time and doesn't expose a way to read
+    /// or change it afterwards, so `prescaler` here must match what `rtc` was already
+    /// constructed with; it's only used to get `Duration`/`Instant` conversions right.
+    pub fn new(rtc: Rtc<RTC>, nvic: &mut NVIC, prescaler: u32) -> Self {
         let mut this = Self {
-            rtc,
-            period: AtomicU32::new(0),
+            rtc: UnsafeCell::new(rtc),
+            period: HalfPeriodCounter::new(),
+            alarms: Mutex::new(RefCell::new(core::array::from_fn(|_| None))),
+            next_seq: AtomicU32::new(0),
+            events: AtomicU32::new(0),
+            evicted_alarms: AtomicU32::new(0),
         };
-        this.init(nvic);
+        this.init(nvic, prescaler);
         this
     }
 
-    fn init(&mut self, nvic: &mut NVIC) {
-        self.rtc.set_compare(rtc::RtcCompareReg::Compare2, 0x800000).unwrap();
+    fn init(&mut self, nvic: &mut NVIC, prescaler: u32) {
+        crate::set_tick_hz(crate::DEFAULT_TICK_HZ / (prescaler + 1));
+
+        let rtc = self.rtc.get_mut();
+        rtc.set_compare(rtc::RtcCompareReg::Compare2, self.period.midpoint()).unwrap();
 
-        self.rtc.clear_counter();
-        self.rtc.enable_counter();
+        rtc.clear_counter();
+        rtc.enable_counter();
 
         // Wait for clear
-        while self.rtc.get_counter() != 0 {}
+        while rtc.get_counter() != 0 {}
+
+        rtc.enable_interrupt(rtc::RtcInterrupt::Overflow, Some(nvic));
+        rtc.enable_interrupt(rtc::RtcInterrupt::Compare2, Some(nvic));
+    }
 
-        self.rtc.enable_interrupt(rtc::RtcInterrupt::Overflow, Some(nvic));
-        self.rtc.enable_interrupt(rtc::RtcInterrupt::Compare2, Some(nvic));
+    /// Shared access to the RTC peripheral, for methods that only need to read state or clear an
+    /// event flag.
+    ///
+    /// Takes a `cs` purely to force every caller to hold one -- see the safety note on
+    /// [`Self::rtc_mut`] for why that matters even for a shared borrow.
+    fn rtc<'cs>(&self, _cs: &'cs CriticalSection) -> &'cs Rtc<RTC> {
+        // SAFETY: see the safety note on `rtc_mut`.
+        unsafe { &*self.rtc.get() }
+    }
+
+    /// Exclusive access to the RTC peripheral, for methods `nrf-hal-common` requires `&mut self`
+    /// for (`set_compare`, `enable_interrupt`, `disable_interrupt`).
+    ///
+    /// # Safety invariant
+    ///
+    /// Both `rtc` and `rtc_mut` require a `CriticalSection`, not just `rtc_mut`: a `&mut Rtc<RTC>`
+    /// handed out here would alias a concurrent `&Rtc<RTC>` from `rtc` just as badly as another
+    /// `&mut`, so a plain `&self` read (as `now()` used to do, with no critical section) could
+    /// race a `rtc_mut` call made from inside the RTC's own interrupt handler. Requiring `cs`
+    /// everywhere -- which on this single-core target means interrupts are masked -- rules that
+    /// out: nothing else can be touching `self.rtc` for as long as the caller holds `cs`.
+    #[allow(clippy::mut_from_ref)]
+    fn rtc_mut<'cs>(&self, _cs: &'cs CriticalSection) -> &'cs mut Rtc<RTC> {
+        // SAFETY: see the safety invariant above.
+        unsafe { &mut *self.rtc.get() }
+    }
+
+    /// Install this driver as the one backing `nrf_time::now()`, `Instant::now()`, `Timer`, and
+    /// `idle()` crate-wide.
+    pub fn register(&'static self) {
+        crate::register_driver(self);
     }
 
     pub fn on_interrupt(&self) {
-        if self.rtc.is_event_triggered(rtc::RtcInterrupt::Overflow) {
-            self.rtc.reset_event(rtc::RtcInterrupt::Overflow);
-            self.next_period();
+        self.events.fetch_add(1, Ordering::Relaxed);
+        cortex_m::interrupt::free(|cs| {
+            if self.rtc(cs).is_event_triggered(rtc::RtcInterrupt::Overflow) {
+                self.rtc(cs).reset_event(rtc::RtcInterrupt::Overflow);
+                self.period.on_overflow();
+                self.rearm(cs);
+            }
+            if self.rtc(cs).is_event_triggered(rtc::RtcInterrupt::Compare2) {
+                self.rtc(cs).reset_event(rtc::RtcInterrupt::Compare2);
+                self.period.on_midpoint();
+                self.rearm(cs);
+            }
+            if self.rtc(cs).is_event_triggered(rtc::RtcInterrupt::Compare0) {
+                self.rtc(cs).reset_event(rtc::RtcInterrupt::Compare0);
+                self.rearm(cs);
+            }
+        });
+    }
+
+    pub fn now(&self) -> u64 {
+        cortex_m::interrupt::free(|cs| self.period.now(self.rtc(cs).get_counter()))
+    }
+
+    /// Number of `on_interrupt` calls observed so far, i.e. how many RTC events (Overflow,
+    /// Compare2, or Compare0) have fired. Used by [`crate::idle`] to detect that one fired while
+    /// it was deciding whether to sleep again.
+    pub fn event_count(&self) -> u32 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    /// Register `waker` to be woken once `now() >= at`.
+    ///
+    /// A slot already registered to an equivalent waker is replaced in place. Otherwise, if all
+    /// [`ALARM_COUNT`] slots are in use by other timers, the one armed longest ago is evicted to
+    /// make room; that timer is left pending forever, so keep concurrently-live
+    /// [`Timer`](crate::Timer)/[`Ticker`](crate::Ticker) instances within `ALARM_COUNT`. Debug
+    /// builds panic the first time this happens, since it's silent data loss for whatever timer
+    /// got evicted; release builds instead count it in [`Self::evicted_alarm_count`], so it's
+    /// still observable without paying for the panic.
+    pub fn schedule_wake(&self, at: u64, waker: &Waker) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        cortex_m::interrupt::free(|cs| {
+            let mut alarms = self.alarms.borrow(cs).borrow_mut();
+            let slot_index = alarms
+                .iter()
+                .position(|slot| slot.as_ref().map_or(false, |alarm| alarm.waker.will_wake(waker)))
+                .or_else(|| alarms.iter().position(|slot| slot.is_none()))
+                .unwrap_or_else(|| {
+                    debug_assert!(
+                        false,
+                        "RtcDriver: all {ALARM_COUNT} alarm slots are in use by distinct timers; \
+                         evicting the oldest one, which will now never fire. Raise ALARM_COUNT or \
+                         reduce the number of concurrently-live Timer/Ticker instances."
+                    );
+                    self.evicted_alarms.fetch_add(1, Ordering::Relaxed);
+                    alarms
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, slot)| slot.as_ref().unwrap().seq)
+                        .unwrap()
+                        .0
+                });
+            alarms[slot_index] = Some(AlarmState {
+                timestamp: at,
+                waker: waker.clone(),
+                seq,
+            });
+            drop(alarms);
+            self.rearm(cs);
+        });
+    }
+
+    /// Number of times [`Self::schedule_wake`] has had to evict an outstanding alarm to make room
+    /// for a new one, because more than [`ALARM_COUNT`] timers were concurrently live. A nonzero
+    /// count means at least one `Timer`/`Ticker` silently never fired.
+    pub fn evicted_alarm_count(&self) -> u32 {
+        self.evicted_alarms.load(Ordering::Relaxed)
+    }
+
+    /// Fire any alarms that are already due, then arm Compare0 for the earliest remaining one.
+    ///
+    /// Must be called with interrupts disabled, since it shares the `alarms` table with
+    /// `on_interrupt`.
+    fn rearm(&self, cs: &CriticalSection) {
+        loop {
+            let mut alarms = self.alarms.borrow(cs).borrow_mut();
+            let now = self.now();
+
+            for slot in alarms.iter_mut() {
+                if matches!(slot, Some(alarm) if alarm.timestamp <= now) {
+                    slot.take().unwrap().waker.wake();
+                }
+            }
+
+            let next = alarms.iter().flatten().map(|alarm| alarm.timestamp).min();
+            drop(alarms);
+
+            let Some(at) = next else {
+                self.rtc_mut(cs).disable_interrupt(rtc::RtcInterrupt::Compare0, None);
+                return;
+            };
+
+            if self.set_alarm(cs, at) {
+                // The counter may have raced past `at` while we were programming the compare
+                // register, which would otherwise stall this alarm for a full overflow cycle.
+                // Loop back around to fire it immediately instead of waiting for the event.
+                if self.now() < at {
+                    return;
+                }
+            } else {
+                // `at` is further away than the current counter window; it will be armed later,
+                // once the next overflow/midpoint event brings it into range.
+                return;
+            }
         }
-        if self.rtc.is_event_triggered(rtc::RtcInterrupt::Compare2) {
-            self.rtc.reset_event(rtc::RtcInterrupt::Compare2);
-            self.next_period();
+    }
+
+    /// Program Compare0 to fire at `at`, if it falls within the current counter window.
+    ///
+    /// Returns `false` (leaving the hardware compare unset) if `at` is a full counter cycle
+    /// (`2^24` ticks) or more away; the caller is expected to retry once the counter catches up.
+    fn set_alarm(&self, cs: &CriticalSection, at: u64) -> bool {
+        if at.saturating_sub(self.now()) >= (1 << RTC_BITS) {
+            return false;
         }
+        let mask = (1u64 << RTC_BITS) - 1;
+        let rtc = self.rtc_mut(cs);
+        rtc.set_compare(rtc::RtcCompareReg::Compare0, (at & mask) as u32).unwrap();
+        rtc.enable_interrupt(rtc::RtcInterrupt::Compare0, None);
+        true
     }
+}
 
-    fn next_period(&self) {
-        let period = self.period.load(Ordering::Relaxed) + 1;
-        self.period.store(period, Ordering::Relaxed);
+impl<RTC: rtc::Instance + Send> crate::Driver for RtcDriver<RTC> {
+    fn now(&self) -> u64 {
+        self.now()
     }
 
-    pub fn now(&self) -> u64 {
-        // `period` MUST be read before `counter`, see comment at the top for details.
-        let period = self.period.load(Ordering::Relaxed);
-        compiler_fence(Ordering::Acquire);
-        let counter = self.rtc.get_counter();
-        calc_now(period, counter)
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        self.schedule_wake(at, waker)
+    }
+
+    fn on_interrupt(&self) {
+        self.on_interrupt()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    #[test]
-    fn test_calc_now() {
-        assert_eq!(calc_now(0, 0x000000), 0x0_000000);
-        assert_eq!(calc_now(0, 0x000001), 0x0_000001);
-        assert_eq!(calc_now(0, 0x7FFFFF), 0x0_7FFFFF);
-        assert_eq!(calc_now(1, 0x7FFFFF), 0x1_7FFFFF);
-        assert_eq!(calc_now(0, 0x800000), 0x0_800000);
-        assert_eq!(calc_now(1, 0x800000), 0x0_800000);
-        assert_eq!(calc_now(1, 0x800001), 0x0_800001);
-        assert_eq!(calc_now(1, 0xFFFFFF), 0x0_FFFFFF);
-        assert_eq!(calc_now(2, 0xFFFFFF), 0x1_FFFFFF);
-        assert_eq!(calc_now(1, 0x000000), 0x1_000000);
-        assert_eq!(calc_now(2, 0x000000), 0x1_000000);
+    fn event_count(&self) -> u32 {
+        self.event_count()
     }
 }
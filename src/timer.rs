@@ -0,0 +1,123 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::{schedule_wake, Duration, Instant};
+
+/// A future that completes at a given `Instant`.
+///
+/// The time until expiry is tracked by the active time driver, which wakes this future's task
+/// once the target instant is reached rather than requiring it to be polled repeatedly.
+///
+/// At most [`crate::ALARM_COUNT`] `Timer`/`Ticker` instances can be outstanding at once; beyond
+/// that, the least-recently (re)armed one is evicted and never completes.
+pub struct Timer {
+    expires_at: Instant,
+}
+
+impl Timer {
+    /// Create a timer that expires at `instant`.
+    pub fn at(instant: Instant) -> Self {
+        Self { expires_at: instant }
+    }
+
+    /// Create a timer that expires `duration` from now.
+    pub fn after(duration: Duration) -> Self {
+        Self::at(Instant::now() + duration)
+    }
+
+    /// Create a timer that expires `secs` seconds from now.
+    pub fn after_secs(secs: u64) -> Self {
+        Self::after(Duration::from_secs(secs))
+    }
+
+    /// Create a timer that expires `millis` milliseconds from now.
+    pub fn after_millis(millis: u64) -> Self {
+        Self::after(Duration::from_millis(millis))
+    }
+
+    /// Create a timer that expires `micros` microseconds from now.
+    pub fn after_micros(micros: u64) -> Self {
+        Self::after(Duration::from_micros(micros))
+    }
+
+    /// Create a timer that expires `nanos` nanoseconds from now.
+    pub fn after_nanos(nanos: u64) -> Self {
+        Self::after(Duration::from_nanos(nanos))
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if Instant::now() >= self.expires_at {
+            return Poll::Ready(());
+        }
+        schedule_wake(self.expires_at.as_ticks(), cx.waker());
+        Poll::Pending
+    }
+}
+
+/// A stream-like timer that yields every `duration`, without drifting from missed ticks.
+///
+/// Shares the same bounded alarm table as [`Timer`]; see its docs for the `ALARM_COUNT` caveat.
+pub struct Ticker {
+    next: Instant,
+    duration: Duration,
+}
+
+impl Ticker {
+    /// Create a new ticker firing every `duration`, starting one `duration` from now.
+    pub fn every(duration: Duration) -> Self {
+        Self {
+            next: Instant::now() + duration,
+            duration,
+        }
+    }
+
+    /// Wait for the next tick.
+    pub async fn next(&mut self) {
+        Timer::at(self.next).await;
+        self.next = self.next + self.duration;
+    }
+}
+
+/// Error returned by [`WithTimeout`] when the wrapped future doesn't complete in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError;
+
+/// A future that resolves to `Err(TimeoutError)` if the wrapped future doesn't complete before
+/// `timeout` elapses.
+pub struct WithTimeout<F: Future> {
+    timer: Timer,
+    future: F,
+}
+
+impl<F: Future> WithTimeout<F> {
+    /// Wrap `future`, failing with [`TimeoutError`] if it hasn't completed after `timeout`.
+    pub fn new(timeout: Duration, future: F) -> Self {
+        Self {
+            timer: Timer::after(timeout),
+            future,
+        }
+    }
+}
+
+impl<F: Future> Future for WithTimeout<F> {
+    type Output = Result<F::Output, TimeoutError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: `self` is not moved out of; both fields are only ever accessed through a pin.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        let timer = unsafe { Pin::new_unchecked(&mut this.timer) };
+        match timer.poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(TimeoutError)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}